@@ -1,23 +1,39 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::get,
-    Router,
+    Json, Router,
 };
-use edgedb_errors::ConstraintViolationError;
+use edgedb_errors::{ConstraintViolationError, NoDataError};
 use edgedb_protocol::value::Value;
-use edgedb_tokio::{create_client, Client, Queryable};
-use serde::Deserialize;
-use std::time::Duration;
-use tokio::{net::TcpListener, time::sleep};
+use edgedb_tokio::{create_client, Client, Error as DbError, Queryable};
+use futures::stream::Stream;
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, env, sync::Arc, time::Duration};
+use tokio::{net::TcpListener, sync::broadcast, time::sleep};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt as _};
 
 fn select_city(filter: &str) -> String {
     let mut output = "
     with city := assert_single((select City filter .name = <str>$0)),
-    select city { 
-        name, 
-        latitude, 
+    select city {
+        name,
+        latitude,
         longitude,
-        conditions: { temperature, time }
+        conditions: {
+            temperature,
+            windspeed,
+            winddirection,
+            humidity,
+            pressure,
+            time
+        }
     } "
     .to_string();
     output.push_str(filter);
@@ -25,11 +41,18 @@ fn select_city(filter: &str) -> String {
 }
 
 fn select_cities(filter: &str) -> String {
-    let mut output = "select City { 
-        name, 
-        latitude, 
+    let mut output = "select City {
+        name,
+        latitude,
         longitude,
-        conditions: { temperature, time }
+        conditions: {
+            temperature,
+            windspeed,
+            winddirection,
+            humidity,
+            pressure,
+            time
+        }
     } "
     .to_string();
     output.push_str(filter);
@@ -48,10 +71,38 @@ fn insert_conditions() -> &'static str {
     "insert Conditions {
         city := assert_single((select City filter .name = <str>$0)),
         temperature := <float64>$1,
-        time := <str>$2 
+        windspeed := <float64>$2,
+        winddirection := <float64>$3,
+        humidity := <float64>$4,
+        pressure := <float64>$5,
+        time := <str>$6
     }"
 }
 
+fn select_city_history(filter: &str) -> String {
+    let mut output = "
+    with city := assert_single((select City filter .name = <str>$0)),
+    select city {
+        name,
+        conditions := (
+            select .conditions
+            filter .time >= <str>$1 and .time <= <str>$2
+            order by .time desc
+            limit <int64>$3
+        ) {
+            temperature,
+            windspeed,
+            winddirection,
+            humidity,
+            pressure,
+            time
+        }
+    } "
+    .to_string();
+    output.push_str(filter);
+    output
+}
+
 fn delete_city() -> &'static str {
     "delete City filter .name = <str>$0"
 }
@@ -60,7 +111,7 @@ fn select_city_names() -> &'static str {
     "select City.name order by City.name"
 }
 
-#[derive(Queryable, Debug)]
+#[derive(Serialize, Queryable, Debug)]
 struct City {
     name: String,
     latitude: f64,
@@ -68,33 +119,197 @@ struct City {
     conditions: Option<Vec<CurrentWeather>>,
 }
 
-#[derive(Deserialize, Queryable, Debug)]
-struct WeatherResult {
-    current_weather: CurrentWeather,
+#[derive(Serialize, Queryable, Debug)]
+struct CityHistory {
+    name: String,
+    conditions: Option<Vec<CurrentWeather>>,
+}
+
+/// Query params for `/conditions/:name/history`. `limit` alone (with no
+/// `from`/`to`) gives the "last N readings" convenience.
+#[derive(Deserialize, Debug)]
+struct HistoryQuery {
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenMeteoResult {
+    current_weather: OpenMeteoCurrentWeather,
+    current: OpenMeteoCurrent,
 }
 
-#[derive(Deserialize, Queryable, Debug)]
+#[derive(Deserialize, Debug)]
+struct OpenMeteoCurrentWeather {
+    temperature: f64,
+    windspeed: f64,
+    winddirection: f64,
+    time: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenMeteoCurrent {
+    relative_humidity_2m: f64,
+    surface_pressure: f64,
+}
+
+#[derive(Serialize, Queryable, Debug, Clone)]
 struct CurrentWeather {
     temperature: f64,
+    windspeed: f64,
+    winddirection: f64,
+    humidity: f64,
+    pressure: f64,
     time: String,
 }
 
-async fn weather_for(
-    latitude: f64,
-    longitude: f64,
-) -> Result<CurrentWeather, anyhow::Error> {
-    let url = format!(
-        "https://api.open-meteo.com/v1/forecast?\
-        latitude={latitude}&longitude={longitude}\
-        &current_weather=true&timezone=CET"
-    );
-    let res = reqwest::get(url).await?.text().await?;
-    let weather_result: WeatherResult = serde_json::from_str(&res)?;
-    Ok(weather_result.current_weather)
+/// A source of current-conditions data. Lets operators swap the backend
+/// (Open-Meteo, OpenWeatherMap, ...) without touching `WeatherApp` or the
+/// database layer.
+#[async_trait::async_trait]
+trait WeatherProvider: Send + Sync {
+    async fn current(
+        &self,
+        lat: f64,
+        lon: f64,
+    ) -> Result<CurrentWeather, anyhow::Error>;
+}
+
+/// Free, no-key weather backend at open-meteo.com.
+struct OpenMeteo;
+
+#[async_trait::async_trait]
+impl WeatherProvider for OpenMeteo {
+    async fn current(
+        &self,
+        lat: f64,
+        lon: f64,
+    ) -> Result<CurrentWeather, anyhow::Error> {
+        let url = format!(
+            "https://api.open-meteo.com/v1/forecast?\
+            latitude={lat}&longitude={lon}\
+            &current_weather=true\
+            &current=relative_humidity_2m,surface_pressure\
+            &timezone=CET"
+        );
+        let res = reqwest::get(url).await?.text().await?;
+        let weather_result: OpenMeteoResult = serde_json::from_str(&res)?;
+        let OpenMeteoCurrentWeather {
+            temperature,
+            windspeed,
+            winddirection,
+            time,
+        } = weather_result.current_weather;
+        let OpenMeteoCurrent {
+            relative_humidity_2m,
+            surface_pressure,
+        } = weather_result.current;
+        Ok(CurrentWeather {
+            temperature,
+            windspeed,
+            winddirection,
+            humidity: relative_humidity_2m,
+            pressure: surface_pressure,
+            time,
+        })
+    }
+}
+
+/// OpenWeatherMap backend. Reads its API key from `OPENWEATHERMAP_API_KEY`.
+struct OpenWeatherMap {
+    api_key: String,
+}
+
+impl OpenWeatherMap {
+    fn from_env() -> Result<Self, anyhow::Error> {
+        let api_key = env::var("OPENWEATHERMAP_API_KEY")?;
+        Ok(Self { api_key })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct OwmResponse {
+    main: OwmMain,
+    wind: OwmWind,
+    dt: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct OwmMain {
+    temp: f64,
+    humidity: f64,
+    pressure: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct OwmWind {
+    speed: f64,
+    deg: f64,
+}
+
+#[async_trait::async_trait]
+impl WeatherProvider for OpenWeatherMap {
+    async fn current(
+        &self,
+        lat: f64,
+        lon: f64,
+    ) -> Result<CurrentWeather, anyhow::Error> {
+        let url = format!(
+            "https://api.openweathermap.org/data/2.5/weather?\
+            lat={lat}&lon={lon}&units=metric&appid={}",
+            self.api_key
+        );
+        let res = reqwest::get(url).await?.text().await?;
+        let owm: OwmResponse = serde_json::from_str(&res)?;
+        Ok(CurrentWeather {
+            temperature: owm.main.temp,
+            windspeed: owm.wind.speed,
+            winddirection: owm.wind.deg,
+            humidity: owm.main.humidity,
+            pressure: owm.main.pressure,
+            time: chrono::DateTime::from_timestamp(owm.dt, 0)
+                .ok_or_else(|| anyhow::anyhow!("invalid timestamp {}", owm.dt))?
+                .to_rfc3339(),
+        })
+    }
+}
+
+/// Runtime configuration loaded from the environment.
+struct Config {
+    jwt_secret: String,
+    jwt_maxage_mins: i64,
+    bind_addr: String,
+}
+
+impl Config {
+    fn from_env() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            jwt_secret: env::var("JWT_SECRET")?,
+            jwt_maxage_mins: env::var("JWT_MAXAGE")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()?,
+            bind_addr: env::var("BIND_ADDR")
+                .unwrap_or_else(|_| "0.0.0.0:3000".to_string()),
+        })
+    }
+}
+
+/// State shared between the background [`WeatherApp`] loop and the Axum
+/// handlers, so new conditions can be pushed to subscribers as soon as
+/// they land in the database.
+#[derive(Clone)]
+struct AppState {
+    db: Client,
+    conditions_tx: broadcast::Sender<(String, CurrentWeather)>,
+    provider: Arc<dyn WeatherProvider>,
+    config: Arc<Config>,
 }
 
 struct WeatherApp {
     db: Client,
+    conditions_tx: broadcast::Sender<(String, CurrentWeather)>,
+    provider: Arc<dyn WeatherProvider>,
 }
 
 impl WeatherApp {
@@ -134,14 +349,36 @@ impl WeatherApp {
             ..
         } in self.get_cities().await?
         {
-            let CurrentWeather { temperature, time } =
-                weather_for(latitude, longitude).await?;
+            let weather = self.provider.current(latitude, longitude).await?;
+            let CurrentWeather {
+                temperature,
+                windspeed,
+                winddirection,
+                humidity,
+                pressure,
+                time,
+            } = weather.clone();
             match self
                 .db
-                .execute(insert_conditions(), &(&name, temperature, time))
+                .execute(
+                    insert_conditions(),
+                    &(
+                        &name,
+                        temperature,
+                        windspeed,
+                        winddirection,
+                        humidity,
+                        pressure,
+                        time,
+                    ),
+                )
                 .await
             {
-                Ok(()) => println!("Inserted new conditions for {}", name),
+                Ok(()) => {
+                    println!("Inserted new conditions for {}", name);
+                    // Ignore the error: it only means no one is subscribed yet.
+                    let _ = self.conditions_tx.send((name, weather));
+                }
                 Err(e) => {
                     if !e.is::<ConstraintViolationError>() {
                         println!("Unexpected error: {e}");
@@ -164,10 +401,88 @@ impl WeatherApp {
     }
 }
 
+/// Errors turned into proper HTTP status codes and a JSON body, so
+/// handlers never have to fake success/failure through plain strings.
+#[derive(Debug)]
+enum AppError {
+    NotFound(String),
+    Upstream(anyhow::Error),
+    Db(DbError),
+    Validation(String),
+    Unauthorized(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            AppError::Upstream(e) => (StatusCode::BAD_GATEWAY, e.to_string()),
+            AppError::Db(e) if e.is::<ConstraintViolationError>() => {
+                (StatusCode::CONFLICT, e.to_string())
+            }
+            AppError::Db(e) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            }
+            AppError::Validation(msg) => (StatusCode::BAD_REQUEST, msg),
+            AppError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+        };
+        (status, Json(ErrorBody { error: message })).into_response()
+    }
+}
+
+/// A plain acknowledgement for mutating routes that don't have a richer
+/// resource to hand back.
+#[derive(Serialize)]
+struct StatusMessage {
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Claims {
+    sub: String,
+    exp: usize,
+}
+
+/// Validates a Bearer JWT on the mutating routes it's layered onto,
+/// leaving `/conditions`, `/city_names`, and `/` public.
+async fn auth(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| {
+            AppError::Unauthorized("missing bearer token".to_string())
+        })?;
+    let claims = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(state.config.jwt_secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map_err(|e| AppError::Unauthorized(format!("invalid token: {e}")))?
+    .claims;
+    println!(
+        "Authenticated request from {} (expires {})",
+        claims.sub, claims.exp
+    );
+    Ok(next.run(req).await)
+}
+
 // Axum functions
 async fn menu() -> &'static str {
     "Routes:
             /conditions/<name>
+            /conditions/<name>/stream
+            /conditions/<name>/history?from=&to=&limit=
             /add_city/<name>/<latitude>/<longitude>
             /remove_city/<name>
             /city_names"
@@ -175,89 +490,190 @@ async fn menu() -> &'static str {
 
 async fn get_conditions(
     Path(city_name): Path<String>,
-    State(client): State<Client>,
-) -> String {
+    State(state): State<AppState>,
+) -> Result<Json<City>, AppError> {
     let query = select_city("filter .name = <str>$0");
-    match client
+    let city = state
+        .db
         .query_required_single::<City, _>(&query, &(&city_name,))
         .await
-    {
-        Ok(city) => {
-            let mut conditions = format!("Conditions for {city_name}:\n\n");
-            for condition in city.conditions.unwrap_or_default() {
-                let (date, hour) =
-                    condition.time.split_once("T").unwrap_or_default();
-                conditions.push_str(&format!("{date} {hour}\t"));
-                conditions.push_str(&format!("{}\n", condition.temperature));
+        .map_err(|e| {
+            if e.is::<NoDataError>() {
+                AppError::NotFound(format!("No city named {city_name}"))
+            } else {
+                AppError::Db(e)
             }
-            conditions
-        }
-        Err(e) => format!("Couldn't find {city_name}: {e}"),
-    }
+        })?;
+    Ok(Json(city))
+}
+
+/// Default bounds used when `from`/`to`/`limit` are omitted, so the query
+/// below always has all four positional arguments to bind.
+const HISTORY_FROM_DEFAULT: &str = "";
+const HISTORY_TO_DEFAULT: &str = "9999-12-31T23:59:59";
+const HISTORY_LIMIT_DEFAULT: i64 = 1_000_000_000;
+
+async fn get_conditions_history(
+    Path(city_name): Path<String>,
+    State(state): State<AppState>,
+    Query(range): Query<HistoryQuery>,
+) -> Result<Json<Vec<CurrentWeather>>, AppError> {
+    let query = select_city_history("filter .name = <str>$0");
+    let city = state
+        .db
+        .query_required_single::<CityHistory, _>(
+            &query,
+            &(
+                &city_name,
+                range.from.unwrap_or_else(|| HISTORY_FROM_DEFAULT.to_string()),
+                range.to.unwrap_or_else(|| HISTORY_TO_DEFAULT.to_string()),
+                range.limit.unwrap_or(HISTORY_LIMIT_DEFAULT),
+            ),
+        )
+        .await
+        .map_err(|e| {
+            if e.is::<NoDataError>() {
+                AppError::NotFound(format!("No city named {city_name}"))
+            } else {
+                AppError::Db(e)
+            }
+        })?;
+    Ok(Json(city.conditions.unwrap_or_default()))
+}
+
+/// Streams each new `(city_name, CurrentWeather)` reading as it is
+/// published by the background [`WeatherApp::run`] loop, filtered down to
+/// the city requested, so clients no longer need to poll `/conditions`.
+async fn stream_conditions(
+    Path(city_name): Path<String>,
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = BroadcastStream::new(state.conditions_tx.subscribe())
+        .filter_map(move |msg| match msg {
+            Ok((name, weather)) if name == city_name => {
+                Some(Ok(Event::default().json_data(weather).unwrap()))
+            }
+            _ => None,
+        });
+    Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
 async fn add_city(
-    State(client): State<Client>,
+    State(state): State<AppState>,
     Path((name, lat, long)): Path<(String, f64, f64)>,
-) -> String {
-    // First make sure that Open-Meteo is okay with it
-    let (temperature, time) = match weather_for(lat, long).await {
-        Ok(c) => (c.temperature, c.time),
-        Err(e) => {
-            return format!("Couldn't get weather info: {e}");
-        }
-    };
-    // Then insert the City
-    if let Err(e) = client.execute(insert_city(), &(&name, lat, long)).await {
-        return e.to_string();
+) -> Result<Json<StatusMessage>, AppError> {
+    if name.trim().is_empty() {
+        return Err(AppError::Validation(
+            "city name must not be empty".to_string(),
+        ));
     }
+    // First make sure that the weather provider is okay with it
+    let weather = state
+        .provider
+        .current(lat, long)
+        .await
+        .map_err(AppError::Upstream)?;
+    // Then insert the City
+    state
+        .db
+        .execute(insert_city(), &(&name, lat, long))
+        .await
+        .map_err(AppError::Db)?;
     // And finally the Conditions
-    if let Err(e) = client
-        .execute(insert_conditions(), &(&name, temperature, time))
+    state
+        .db
+        .execute(
+            insert_conditions(),
+            &(
+                &name,
+                weather.temperature,
+                weather.windspeed,
+                weather.winddirection,
+                weather.humidity,
+                weather.pressure,
+                weather.time,
+            ),
+        )
         .await
-    {
-        return format!(
-            "Inserted City {name} but couldn't insert conditions: {e}"
-        );
-    }
-    format!("Inserted city {name}!")
+        .map_err(AppError::Db)?;
+    Ok(Json(StatusMessage {
+        message: format!("Inserted city {name}!"),
+    }))
 }
 
 async fn remove_city(
     Path(name): Path<String>,
-    State(client): State<Client>,
-) -> String {
-    match client.query::<Value, _>(delete_city(), &(&name,)).await {
-        Ok(v) if v.is_empty() => format!("No city {name} found to remove!"),
-        Ok(_) => format!("City {name} removed!"),
-        Err(e) => e.to_string(),
+    State(state): State<AppState>,
+) -> Result<Json<StatusMessage>, AppError> {
+    let deleted = state
+        .db
+        .query::<Value, _>(delete_city(), &(&name,))
+        .await
+        .map_err(AppError::Db)?;
+    if deleted.is_empty() {
+        return Err(AppError::NotFound(format!(
+            "No city {name} found to remove!"
+        )));
     }
+    Ok(Json(StatusMessage {
+        message: format!("City {name} removed!"),
+    }))
 }
 
-async fn city_names(State(client): State<Client>) -> String {
-    match client.query::<String, _>(select_city_names(), &()).await {
-        Ok(cities) => format!("{cities:#?}"),
-        Err(e) => e.to_string(),
-    }
+async fn city_names(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<String>>, AppError> {
+    let cities = state
+        .db
+        .query::<String, _>(select_city_names(), &())
+        .await
+        .map_err(AppError::Db)?;
+    Ok(Json(cities))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    let config = Config::from_env()?;
+    println!(
+        "Config loaded: JWT max-age {} minutes, binding to {}",
+        config.jwt_maxage_mins, config.bind_addr
+    );
     let client = create_client().await?;
-    let weather_app = WeatherApp { db: client.clone() };
+    let (conditions_tx, _) = broadcast::channel(16);
+    let provider: Arc<dyn WeatherProvider> = match OpenWeatherMap::from_env() {
+        Ok(owm) => Arc::new(owm),
+        Err(_) => Arc::new(OpenMeteo),
+    };
+    let weather_app = WeatherApp {
+        db: client.clone(),
+        conditions_tx: conditions_tx.clone(),
+        provider: provider.clone(),
+    };
     weather_app.init().await;
     tokio::task::spawn(async move {
         weather_app.run().await;
     });
+    let state = AppState {
+        db: client,
+        conditions_tx,
+        provider,
+        config: Arc::new(config),
+    };
+    let protected = Router::new()
+        .route("/add_city/:name/:latitude/:longitude", get(add_city))
+        .route("/remove_city/:name", get(remove_city))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth));
+    let bind_addr = state.config.bind_addr.clone();
     let app = Router::new()
         .route("/", get(menu))
         .route("/conditions/:name", get(get_conditions))
-        .route("/add_city/:name/:latitude/:longitude", get(add_city))
-        .route("/remove_city/:name", get(remove_city))
+        .route("/conditions/:name/stream", get(stream_conditions))
+        .route("/conditions/:name/history", get(get_conditions_history))
         .route("/city_names", get(city_names))
-        .with_state(client)
+        .merge(protected)
+        .with_state(state)
         .into_make_service();
-    let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    let listener = TcpListener::bind(bind_addr).await.unwrap();
     axum::serve(listener, app).await.unwrap();
     Ok(())
 }